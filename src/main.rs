@@ -13,12 +13,338 @@ use structopt::{
 
 use validator::{Validate, ValidationError};
 
-use chrono::{DateTime, Datelike, Days, NaiveTime, Timelike, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeDelta, Timelike, Utc};
 use chrono_tz::Europe::Tallinn;
 use nonempty::NonEmpty;
 use ordered_float::NotNan;
 use serde::Deserialize;
 
+/// Checked local-time arithmetic that turns the DST edge cases around the
+/// spring/autumn transitions into recoverable errors instead of panics.
+mod time {
+    use chrono::{DateTime, Days, LocalResult, NaiveDate, NaiveTime, TimeZone};
+    use chrono_tz::Tz;
+    use std::error::Error;
+    use std::fmt;
+
+    #[derive(Debug)]
+    pub enum TimeError {
+        /// Local midnight does not exist on this date because it lands in a DST
+        /// gap. (An ambiguous midnight in a fold resolves to its earliest instant.)
+        Unresolvable(String),
+        /// Date arithmetic overflowed the representable range.
+        Overflow,
+    }
+
+    impl fmt::Display for TimeError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                TimeError::Unresolvable(when) => {
+                    write!(f, "local midnight is unresolvable on {when}")
+                }
+                TimeError::Overflow => write!(f, "date arithmetic overflowed"),
+            }
+        }
+    }
+
+    impl Error for TimeError {}
+
+    /// Resolve a local wall-clock instant, taking the earliest candidate across
+    /// a fold and rejecting instants that fall in a gap.
+    fn resolve(when: String, result: LocalResult<DateTime<Tz>>) -> Result<DateTime<Tz>, TimeError> {
+        match result {
+            LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => Ok(dt),
+            LocalResult::None => Err(TimeError::Unresolvable(when)),
+        }
+    }
+
+    /// Local midnight on a given date in `tz`.
+    pub fn local_date_start(tz: Tz, date: NaiveDate) -> Result<DateTime<Tz>, TimeError> {
+        resolve(
+            date.to_string(),
+            tz.from_local_datetime(&date.and_time(NaiveTime::MIN)),
+        )
+    }
+
+    /// Local midnight of the day containing `dt`.
+    pub fn start_of_day(dt: DateTime<Tz>) -> Result<DateTime<Tz>, TimeError> {
+        local_date_start(dt.timezone(), dt.date_naive())
+    }
+
+    /// Start of the local day following `dt`'s day. Used as that day's exclusive
+    /// end so 23- and 25-hour DST days size their window correctly.
+    pub fn next_day_start(dt: DateTime<Tz>) -> Result<DateTime<Tz>, TimeError> {
+        let date = dt
+            .date_naive()
+            .checked_add_days(Days::new(1))
+            .ok_or(TimeError::Overflow)?;
+        local_date_start(dt.timezone(), date)
+    }
+}
+
+/// A compact iCal RRULE subset used to restrict when a device may run.
+mod schedule {
+    use chrono::{DateTime, Datelike, NaiveDate, Timelike, Weekday};
+    use chrono_tz::Tz;
+    use serde::Deserialize;
+    use std::collections::{BTreeSet, HashSet};
+    use std::error::Error;
+    use std::fmt;
+
+    /// An availability window attached to a [`super::Device`]: an RRULE string
+    /// plus explicit exception dates the rule never permits.
+    #[derive(Deserialize)]
+    pub struct Schedule {
+        rrule: String,
+        #[serde(default)]
+        except: Vec<NaiveDate>,
+    }
+
+    #[derive(Debug)]
+    pub struct RuleError(String);
+
+    impl fmt::Display for RuleError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid schedule: {}", self.0)
+        }
+    }
+
+    impl Error for RuleError {}
+
+    enum Freq {
+        Daily,
+        Weekly,
+    }
+
+    /// A parsed RRULE: optional `BYDAY`/`BYHOUR` restrictions and exception
+    /// dates. `FREQ` is validated at parse time but does not change evaluation —
+    /// `BYDAY`/`BYHOUR` restrict the recurrence under both `DAILY` and `WEEKLY`
+    /// (per RFC 5545).
+    pub struct Rule {
+        byday: HashSet<Weekday>,
+        byhour: BTreeSet<u32>,
+        except: BTreeSet<NaiveDate>,
+    }
+
+    fn parse_weekday(token: &str) -> Result<Weekday, RuleError> {
+        match token.to_ascii_uppercase().as_str() {
+            "MO" => Ok(Weekday::Mon),
+            "TU" => Ok(Weekday::Tue),
+            "WE" => Ok(Weekday::Wed),
+            "TH" => Ok(Weekday::Thu),
+            "FR" => Ok(Weekday::Fri),
+            "SA" => Ok(Weekday::Sat),
+            "SU" => Ok(Weekday::Sun),
+            other => Err(RuleError(format!("unknown BYDAY value: {other}"))),
+        }
+    }
+
+    impl Schedule {
+        /// Parse the RRULE string into an evaluable [`Rule`].
+        pub fn rule(&self) -> Result<Rule, RuleError> {
+            let mut freq = None;
+            let mut byday = HashSet::new();
+            let mut byhour = BTreeSet::new();
+            for part in self.rrule.split(';').filter(|s| !s.is_empty()) {
+                let (key, value) = part
+                    .split_once('=')
+                    .ok_or_else(|| RuleError(format!("malformed RRULE part: {part}")))?;
+                match key.to_ascii_uppercase().as_str() {
+                    "FREQ" => {
+                        freq = Some(match value.to_ascii_uppercase().as_str() {
+                            "DAILY" => Freq::Daily,
+                            "WEEKLY" => Freq::Weekly,
+                            other => {
+                                return Err(RuleError(format!("unsupported FREQ: {other}")));
+                            }
+                        })
+                    }
+                    "BYDAY" => {
+                        for day in value.split(',') {
+                            byday.insert(parse_weekday(day)?);
+                        }
+                    }
+                    "BYHOUR" => {
+                        for hour in value.split(',') {
+                            byhour.insert(
+                                hour.parse::<u32>()
+                                    .map_err(|_| RuleError(format!("invalid BYHOUR: {hour}")))?,
+                            );
+                        }
+                    }
+                    other => return Err(RuleError(format!("unsupported RRULE key: {other}"))),
+                }
+            }
+            // FREQ is required and must be supported, but DAILY/WEEKLY evaluate
+            // identically here since BYDAY/BYHOUR apply under both.
+            freq.ok_or_else(|| RuleError("RRULE is missing FREQ".into()))?;
+            Ok(Rule {
+                byday,
+                byhour,
+                except: self.except.iter().copied().collect(),
+            })
+        }
+    }
+
+    impl Rule {
+        /// Whether this rule permits the given local instant. Each instant is
+        /// tested independently, so a window such as `BYHOUR=22,23,0,..,5`
+        /// naturally spans local midnight.
+        pub fn allows(&self, local: DateTime<Tz>) -> bool {
+            if self.except.contains(&local.date_naive()) {
+                return false;
+            }
+            let day_ok = self.byday.is_empty() || self.byday.contains(&local.weekday());
+            let hour_ok = self.byhour.is_empty() || self.byhour.contains(&local.hour());
+            day_ok && hour_ok
+        }
+    }
+}
+
+/// A local, file-backed store of fetched prices so repeated runs, `--dry-run`
+/// and mid-day restarts can reuse data without hitting the Elering endpoint.
+mod cache {
+    use crate::Price;
+    use chrono::DateTime;
+    use chrono_tz::Tz;
+    use nonempty::NonEmpty;
+    use ordered_float::NotNan;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::error::Error;
+    use std::path::PathBuf;
+
+    /// On-disk representation: unix-second timestamps mapped to market prices.
+    /// Keys are strings because TOML table keys must be strings.
+    #[derive(Default, Serialize, Deserialize)]
+    struct Stored {
+        #[serde(default)]
+        market: BTreeMap<String, f32>,
+    }
+
+    pub struct Cache {
+        path: PathBuf,
+        stored: Stored,
+    }
+
+    fn to_prices(entries: &BTreeMap<String, f32>, start: i64, end: i64) -> Vec<Price> {
+        entries
+            .iter()
+            .filter_map(|(k, v)| k.parse::<i64>().ok().map(|ts| (ts, v)))
+            .filter(|(ts, _)| *ts >= start && *ts <= end)
+            .filter_map(|(ts, v)| {
+                Some(Price {
+                    timestamp: DateTime::from_timestamp(ts, 0)?,
+                    price: NotNan::new(*v).ok()?,
+                })
+            })
+            .collect()
+    }
+
+    fn insert(entries: &mut BTreeMap<String, f32>, prices: &NonEmpty<Price>) {
+        for p in prices.iter() {
+            entries.insert(p.timestamp.timestamp().to_string(), p.price.into_inner());
+        }
+    }
+
+    /// Whether the cached points cover the whole `[start, end]` range with no
+    /// interior holes. Requires at least two points, contiguous spacing (no gap
+    /// larger than the base slot), and endpoints reaching both bounds (allowing
+    /// one slot of slack at the tail, since the last point sits a slot before
+    /// the exclusive end).
+    fn covers(cached: &[Price], start: i64, end: i64) -> bool {
+        if cached.len() < 2 {
+            return false;
+        }
+        let gaps: Vec<i64> = cached
+            .windows(2)
+            .map(|w| w[1].timestamp.timestamp() - w[0].timestamp.timestamp())
+            .collect();
+        let step = gaps.iter().copied().min().unwrap_or(0);
+        let contiguous = step > 0 && gaps.iter().all(|g| *g <= step);
+        let first = cached.first().unwrap().timestamp.timestamp();
+        let last = cached.last().unwrap().timestamp.timestamp();
+        contiguous && first <= start && last >= end - step
+    }
+
+    impl Cache {
+        /// Open the cache file, starting empty if it does not yet exist.
+        pub fn open(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+            let stored = match std::fs::read_to_string(&path) {
+                Ok(contents) => toml::from_str(&contents)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Stored::default(),
+                Err(e) => return Err(e.into()),
+            };
+            Ok(Cache { path, stored })
+        }
+
+        /// Cached market prices that fall within `[start, end]`, if they cover
+        /// the whole range; otherwise `None`.
+        pub fn covered_market(&self, start: i64, end: i64) -> Option<NonEmpty<Price>> {
+            let prices = to_prices(&self.stored.market, start, end);
+            covers(&prices, start, end)
+                .then(|| NonEmpty::from_vec(prices))
+                .flatten()
+        }
+
+        /// Cached market prices within `[start, end]`, covered or not.
+        pub fn market(&self, start: i64, end: i64) -> Option<NonEmpty<Price>> {
+            NonEmpty::from_vec(to_prices(&self.stored.market, start, end))
+        }
+
+        /// The sub-ranges of `[start, end]` not already present in the cache, so
+        /// only the gaps need to be requested from the API. Slot spacing is
+        /// inferred from the cached points (defaulting to hourly).
+        pub fn missing_ranges(
+            &self,
+            start: &DateTime<Tz>,
+            end: &DateTime<Tz>,
+        ) -> Vec<(DateTime<Tz>, DateTime<Tz>)> {
+            let tz = start.timezone();
+            let (start_ts, end_ts) = (start.timestamp(), end.timestamp());
+            let cached = to_prices(&self.stored.market, start_ts, end_ts);
+            let step = cached
+                .windows(2)
+                .map(|w| w[1].timestamp.timestamp() - w[0].timestamp.timestamp())
+                .min()
+                .unwrap_or(3600)
+                .max(1);
+
+            let mut ranges = Vec::new();
+            let mut cursor = start_ts;
+            for p in &cached {
+                let ts = p.timestamp.timestamp();
+                if ts > cursor {
+                    ranges.push((cursor, ts - 1));
+                }
+                cursor = cursor.max(ts + step);
+            }
+            if cursor <= end_ts {
+                ranges.push((cursor, end_ts));
+            }
+
+            ranges
+                .into_iter()
+                .filter_map(|(a, b)| {
+                    Some((
+                        DateTime::from_timestamp(a, 0)?.with_timezone(&tz),
+                        DateTime::from_timestamp(b, 0)?.with_timezone(&tz),
+                    ))
+                })
+                .collect()
+        }
+
+        pub fn record_market(&mut self, prices: &NonEmpty<Price>) {
+            insert(&mut self.stored.market, prices);
+        }
+
+        pub fn save(&self) -> Result<(), Box<dyn Error>> {
+            std::fs::write(&self.path, toml::to_string(&self.stored)?)?;
+            Ok(())
+        }
+    }
+}
+
 fn must_be_true(v: &bool) -> Result<(), ValidationError> {
     if *v {
         Ok(())
@@ -40,6 +366,25 @@ fn validate_constraints(v: &Device) -> Result<(), ValidationError> {
         Err(ValidationError::new(
             "ratio_max must be bigger than ratio_min",
         ))
+    } else if v.earliest.is_some() || v.latest.is_some() {
+        if v.min_runtime.is_none() {
+            Err(ValidationError::new(
+                "earliest/latest can only be specified together with min_runtime",
+            ))
+        } else if let (Some(earliest), Some(latest)) = (v.earliest, v.latest)
+            && earliest >= latest
+        {
+            Err(ValidationError::new("earliest must be before latest"))
+        } else if let (Some(min_runtime), Some(earliest), Some(latest)) =
+            (v.min_runtime, v.earliest, v.latest)
+            && (latest - earliest).to_std().unwrap_or_default() < min_runtime
+        {
+            Err(ValidationError::new(
+                "min_runtime does not fit between earliest and latest",
+            ))
+        } else {
+            Ok(())
+        }
     } else {
         Ok(())
     }
@@ -52,11 +397,32 @@ struct Opt {
     #[structopt(short = "n", long)]
     dry_run: bool,
 
+    /// Never hit the Elering endpoint; serve prices from the cache only
+    #[structopt(long)]
+    offline: bool,
+
+    /// Local price cache file
+    #[structopt(long, parse(from_os_str), default_value = "elekter-cache.toml")]
+    cache: PathBuf,
+
+    /// Pull a historical price range into the cache and exit, e.g.
+    /// `--backfill 2024-01-01..2024-02-01`
+    #[structopt(long)]
+    backfill: Option<String>,
+
     /// TOML configuration file
     #[structopt(parse(from_os_str))]
     config: PathBuf,
 }
 
+/// Parse a `FROM..TO` inclusive date range as used by `--backfill`.
+fn parse_date_range(spec: &str) -> Result<(NaiveDate, NaiveDate), Box<dyn std::error::Error>> {
+    let (from, to) = spec
+        .split_once("..")
+        .ok_or("backfill range must be written as FROM..TO")?;
+    Ok((from.trim().parse()?, to.trim().parse()?))
+}
+
 #[derive(Deserialize, Validate)]
 struct Conf {
     package: Package,
@@ -76,10 +442,43 @@ struct Device {
     ratio_max: Option<NotNan<f32>>,
     #[serde(default, with = "humantime_serde::option")]
     window: Option<Duration>,
+    /// Reserve a single contiguous block of this length at the cheapest
+    /// point in the day (EV charger / boiler "run for N hours" mode).
+    #[serde(default, with = "humantime_serde::option")]
+    min_runtime: Option<Duration>,
+    /// Earliest local time-of-day the contiguous block may start.
+    earliest: Option<NaiveTime>,
+    /// Latest local time-of-day the contiguous block may end.
+    latest: Option<NaiveTime>,
+    /// Decision-slot width. Finer price points are aggregated into slots of
+    /// this size before the constraints run; omit to reason at the native API
+    /// resolution.
+    #[serde(default, with = "humantime_serde::option")]
+    resolution: Option<Duration>,
+    /// How finer points are reduced to one representative price per slot.
+    #[serde(default)]
+    reduction: Reduction,
+    /// iCal RRULE availability window; the device is only ever eligible within
+    /// the instants this rule permits.
+    schedule: Option<schedule::Schedule>,
     cmd_on: NonEmpty<String>,
     cmd_off: NonEmpty<String>,
 }
 
+/// Reduction used when aggregating finer price points into a coarser decision
+/// slot (OHLC-style candle batching).
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Reduction {
+    /// Arithmetic mean of the points in the slot.
+    #[default]
+    Mean,
+    /// Cheapest point in the slot.
+    Min,
+    /// Mean weighted by how long each point is in effect.
+    TimeWeighted,
+}
+
 #[derive(Deserialize, Validate)]
 struct PriceResponse {
     #[validate(custom(function = "must_be_true", message = "success must be true"))]
@@ -96,17 +495,88 @@ struct Price {
     timestamp: DateTime<Utc>,
     price: NotNan<f32>,
 }
+/// A pluggable tariff model, selected in the TOML `[package]` section via a
+/// `type` tag. Each variant knows how to turn a raw market `Price` into the
+/// consumer price the scheduler reasons about, so new network packages can be
+/// modelled without touching the scheduling code.
 #[derive(Deserialize)]
-struct Package {
-    day: NotNan<f32>,
-    night: NotNan<f32>,
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Package {
+    /// The classic Estonian day/night split (07–22 on weekdays is `day`).
+    TwoTariff { day: NotNan<f32>, night: NotNan<f32> },
+    /// A single flat grid rate applied at every hour.
+    Flat { rate: NotNan<f32> },
+    /// Arbitrary `(weekday_set, hour_range, rate)` bands with a fallback rate
+    /// for instants that match no band.
+    MultiBand {
+        bands: Vec<Band>,
+        #[serde(default)]
+        fallback: NotNan<f32>,
+    },
+    /// A percentage (VAT) plus a fixed transmission fee layered on top of the
+    /// raw market price: `market * (1 + vat/100) + transmission`.
+    Markup {
+        #[serde(default)]
+        vat: NotNan<f32>,
+        #[serde(default)]
+        transmission: NotNan<f32>,
+    },
+}
+
+/// One band of a [`Package::MultiBand`] tariff. `days` lists ISO weekday
+/// numbers (Monday = 1 … Sunday = 7) and `hours` is an inclusive-start,
+/// exclusive-end `[from, to)` local-hour range.
+#[derive(Deserialize)]
+struct Band {
+    days: BTreeSet<u32>,
+    hours: [u32; 2],
+    rate: NotNan<f32>,
+}
+
+impl Package {
+    /// Adjust a raw market price into the consumer price for this tariff.
+    fn adjust(&self, price: &Price) -> Price {
+        let local_time = price.timestamp.with_timezone(&Tallinn);
+        let hour = local_time.hour();
+        let weekday = local_time.weekday().number_from_monday();
+        let adjusted = match self {
+            Package::TwoTariff { day, night } => {
+                let rate = if (7..22).contains(&hour) && weekday < 6 {
+                    *day
+                } else {
+                    *night
+                };
+                price.price + rate
+            }
+            Package::Flat { rate } => price.price + *rate,
+            Package::MultiBand { bands, fallback } => {
+                let rate = bands
+                    .iter()
+                    .find(|b| {
+                        b.days.contains(&weekday) && (b.hours[0]..b.hours[1]).contains(&hour)
+                    })
+                    .map(|b| b.rate)
+                    .unwrap_or(*fallback);
+                price.price + rate
+            }
+            Package::Markup { vat, transmission } => {
+                price.price * (NotNan::new(1.0).unwrap() + *vat / NotNan::new(100.0).unwrap())
+                    + *transmission
+            }
+        };
+        Price {
+            price: adjusted,
+            ..*price
+        }
+    }
 }
 
 fn load_config(file: &PathBuf) -> Result<Conf, Box<dyn std::error::Error>> {
     Ok(toml::from_str(&std::fs::read_to_string(file)?)?)
 }
 
-fn fetch_prices(
+/// Request a single `[start, end]` range of market prices from Elering.
+fn request_prices(
     start: &DateTime<Tz>,
     end: &DateTime<Tz>,
 ) -> Result<NonEmpty<Price>, Box<dyn std::error::Error>> {
@@ -120,19 +590,117 @@ fn fetch_prices(
     Ok(prices.data.ee)
 }
 
-fn add_grid_rate(price: &Price, package: &Package) -> Price {
-    let local_time = price.timestamp.with_timezone(&Tallinn);
-    let local_time_hour = local_time.hour();
-    let current_grid =
-        if (7..22).contains(&local_time_hour) && local_time.weekday().number_from_monday() < 6 {
-            package.day
-        } else {
-            package.night
+fn fetch_prices(
+    start: &DateTime<Tz>,
+    end: &DateTime<Tz>,
+    cache: &mut cache::Cache,
+    offline: bool,
+) -> Result<NonEmpty<Price>, Box<dyn std::error::Error>> {
+    // serve fully-cached ranges without touching the network
+    if let Some(cached) = cache.covered_market(start.timestamp(), end.timestamp()) {
+        return Ok(cached);
+    }
+    if offline {
+        return cache
+            .market(start.timestamp(), end.timestamp())
+            .ok_or_else(|| "no cached prices for requested range in offline mode".into());
+    }
+
+    // consult the cache first and only request the ranges it is missing,
+    // persisting each fetched gap immediately so a mid-day restart reuses it
+    let mut prices: Vec<Price> = cache
+        .market(start.timestamp(), end.timestamp())
+        .map(|cached| cached.into_iter().collect())
+        .unwrap_or_default();
+    for (from, to) in cache.missing_ranges(start, end) {
+        let fetched = request_prices(&from, &to)?;
+        cache.record_market(&fetched);
+        cache.save()?;
+        prices.extend(fetched);
+    }
+
+    prices.sort_by_key(|p| p.timestamp);
+    prices.dedup_by_key(|p| p.timestamp);
+    NonEmpty::from_vec(prices).ok_or_else(|| "no prices available for requested range".into())
+}
+
+/// Maps each decision slot back to the underlying native instants so command
+/// scheduling still fires at the correct timestamps.
+type SlotMapping = BTreeMap<DateTime<Utc>, Vec<DateTime<Utc>>>;
+
+/// Average spacing between consecutive price points, in seconds. Derived from
+/// the actual span so a 23- or 25-hour DST day still yields the right slot
+/// width rather than assuming a fixed 86400-second day. Never returns 0, so a
+/// single-slot day (e.g. an over-coarse `resolution`) cannot cause a
+/// divide-by-zero downstream.
+fn interval_secs(prices: &NonEmpty<Price>) -> usize {
+    let span =
+        (prices.last().timestamp.timestamp() - prices.first().timestamp.timestamp()) as usize;
+    (span / (prices.len() - 1).max(1)).max(1)
+}
+
+/// Group the price stream into fixed-width decision slots keyed by floored
+/// timestamp, reduce each bucket to a single representative price, and return
+/// both the coarser stream and a mapping from each slot back to the underlying
+/// instants so command scheduling still fires at the native resolution.
+fn aggregate(
+    prices: &NonEmpty<Price>,
+    resolution: Duration,
+    reduction: Reduction,
+) -> (NonEmpty<Price>, SlotMapping) {
+    let width = (resolution.as_secs() as i64).max(1);
+    let interval = interval_secs(prices) as i64;
+    let pts = prices.iter().collect_vec();
+
+    // bucket each point by its floored slot, remembering how long it is in effect
+    let mut buckets: BTreeMap<i64, Vec<(&Price, i64)>> = BTreeMap::new();
+    for (i, p) in pts.iter().enumerate() {
+        let weight = pts
+            .get(i + 1)
+            .map(|n| n.timestamp.timestamp() - p.timestamp.timestamp())
+            .unwrap_or(interval)
+            .max(1);
+        let key = p.timestamp.timestamp().div_euclid(width) * width;
+        buckets.entry(key).or_default().push((p, weight));
+    }
+
+    let mut slots = Vec::with_capacity(buckets.len());
+    let mut mapping = BTreeMap::new();
+    for (key, members) in buckets {
+        let timestamp = DateTime::from_timestamp(key, 0).unwrap_or(members[0].0.timestamp);
+        let price = match reduction {
+            Reduction::Mean => {
+                members.iter().map(|(p, _)| p.price).sum::<NotNan<f32>>()
+                    / NotNan::new(members.len() as f32).unwrap()
+            }
+            Reduction::Min => members.iter().map(|(p, _)| p.price).min().unwrap(),
+            Reduction::TimeWeighted => {
+                let total: i64 = members.iter().map(|(_, w)| *w).sum();
+                members
+                    .iter()
+                    .map(|(p, w)| p.price * NotNan::new(*w as f32).unwrap())
+                    .sum::<NotNan<f32>>()
+                    / NotNan::new(total as f32).unwrap()
+            }
         };
-    Price {
-        price: price.price + current_grid,
-        ..*price
+        mapping.insert(timestamp, members.iter().map(|(p, _)| p.timestamp).collect());
+        slots.push(Price { timestamp, price });
     }
+
+    (NonEmpty::from_vec(slots).unwrap(), mapping)
+}
+
+/// Materialize the set of instants a schedule permits for the given day's
+/// price points.
+fn permitted_instants(
+    prices: &NonEmpty<Price>,
+    rule: &schedule::Rule,
+) -> BTreeSet<DateTime<Utc>> {
+    prices
+        .iter()
+        .filter(|p| rule.allows(p.timestamp.with_timezone(&Tallinn)))
+        .map(|p| p.timestamp)
+        .collect()
 }
 
 fn satisfy_constraints(prices: &NonEmpty<Price>, device: &Device) -> BTreeSet<DateTime<Utc>> {
@@ -145,9 +713,7 @@ fn satisfy_constraints(prices: &NonEmpty<Price>, device: &Device) -> BTreeSet<Da
                 enabled.insert(p.timestamp);
             });
         if let Some(ratio_max) = device.ratio_max {
-            let interval = (prices.last().timestamp.timestamp()
-                - prices.first().timestamp.timestamp()) as usize
-                / (prices.len() - 1);
+            let interval = interval_secs(prices);
             let window_size = device
                 .window
                 .map(|dur| dur.as_secs() as usize / interval)
@@ -165,9 +731,7 @@ fn satisfy_constraints(prices: &NonEmpty<Price>, device: &Device) -> BTreeSet<Da
         }
     }
     if let Some(ratio_min) = device.ratio_min {
-        let interval = (prices.last().timestamp.timestamp() - prices.first().timestamp.timestamp())
-            as usize
-            / (prices.len() - 1);
+        let interval = interval_secs(prices);
         let window_size = device
             .window
             .map(|dur| dur.as_secs() as usize / interval)
@@ -183,6 +747,51 @@ fn satisfy_constraints(prices: &NonEmpty<Price>, device: &Device) -> BTreeSet<Da
                 });
         }
     }
+    // A contiguous run takes precedence: place a single length-k block at the
+    // cheapest feasible window and force those slots enabled regardless of the
+    // ratio constraints above.
+    if let Some(min_runtime) = device.min_runtime {
+        let interval = interval_secs(prices) as i64;
+        let k = (min_runtime.as_secs() as usize)
+            .div_ceil(interval as usize)
+            .max(1);
+        let points = prices.iter().collect_vec();
+        // measure the block against local midnight of the processed day so the
+        // end instant does not wrap to 00:00 and spuriously satisfy `latest`; a
+        // block ending at midnight is offset 86400, not 0
+        let day_start = prices.first().timestamp.with_timezone(&Tallinn);
+        let midnight_ts = day_start
+            .date_naive()
+            .and_time(NaiveTime::MIN)
+            .and_local_timezone(Tallinn)
+            .single()
+            .map(|dt| dt.timestamp())
+            .unwrap_or(day_start.timestamp());
+        // the block starts at the first point and runs until the last point's
+        // slot ends (`last start + interval`); bounds are seconds from midnight
+        let block_in_bounds = |w: &[&Price]| {
+            let start_off = w.first().unwrap().timestamp.timestamp() - midnight_ts;
+            let end_off = w.last().unwrap().timestamp.timestamp() + interval - midnight_ts;
+            let earliest_ok = device
+                .earliest
+                .map(|e| start_off >= e.num_seconds_from_midnight() as i64)
+                .unwrap_or(true);
+            let latest_ok = device
+                .latest
+                .map(|l| end_off <= l.num_seconds_from_midnight() as i64)
+                .unwrap_or(true);
+            earliest_ok && latest_ok
+        };
+        if let Some(start) = points
+            .windows(k)
+            .filter(|w| block_in_bounds(w))
+            .min_by_key(|w| w.iter().map(|p| p.price).sum::<NotNan<f32>>())
+        {
+            start.iter().for_each(|p| {
+                enabled.insert(p.timestamp);
+            });
+        }
+    }
     enabled
 }
 
@@ -192,39 +801,68 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let conf = load_config(&opt.config)?;
     conf.validate()?;
 
-    for day in std::iter::successors(
-        Some(
-            Utc::now()
-                .with_timezone(&Tallinn)
-                .with_time(NaiveTime::MIN)
-                .unwrap(),
-        ),
-        |d| Some(d.checked_add_days(Days::new(1)).unwrap()),
-    ) {
-        // load market prices for one full day
-        let market_prices = fetch_prices(
-            &day,
-            &day.with_time(
-                NaiveTime::from_num_seconds_from_midnight_opt(24 * 60 * 60 - 1, 0).unwrap(),
-            )
-            .unwrap(),
-        )?;
+    // parse every device's RRULE once up front so malformed rules fail early
+    let mut schedules: BTreeMap<&String, schedule::Rule> = BTreeMap::new();
+    for (name, device) in &conf.devices {
+        if let Some(sched) = &device.schedule {
+            schedules.insert(name, sched.rule()?);
+        }
+    }
+
+    let mut cache = cache::Cache::open(opt.cache.clone())?;
+
+    // backfill mode: pull a historical range into the cache, then exit
+    if let Some(spec) = &opt.backfill {
+        let (from, to) = parse_date_range(spec)?;
+        let mut day = time::local_date_start(Tallinn, from)?;
+        let end = time::local_date_start(Tallinn, to)?;
+        while day <= end {
+            let next_day = time::next_day_start(day)?;
+            // fetch_prices fills and persists the cache for each day
+            fetch_prices(&day, &(next_day - TimeDelta::seconds(1)), &mut cache, opt.offline)?;
+            day = next_day;
+        }
+        return Ok(());
+    }
+
+    let mut day = time::start_of_day(Utc::now().with_timezone(&Tallinn))?;
+    loop {
+        // the day's exclusive end is the start of the next local day, so 23- and
+        // 25-hour DST days cover exactly their own hours
+        let next_day = time::next_day_start(day)?;
+
+        // load market prices for one full day (cache-first)
+        let market_prices =
+            fetch_prices(&day, &(next_day - TimeDelta::seconds(1)), &mut cache, opt.offline)?;
 
         // adjust market prices with day/night rates based on selected network package
-        let consumer_prices = market_prices.map(|p| add_grid_rate(&p, &conf.package));
+        let consumer_prices = market_prices.map(|p| conf.package.adjust(&p));
 
         // calculate enabled times for devices based on constraints
         let thresholds: BTreeMap<&String, (&Device, BTreeSet<DateTime<Utc>>)> = conf
             .devices
             .iter()
             .map(|(name, constraints)| {
-                (
-                    name,
-                    (
-                        constraints,
-                        satisfy_constraints(&consumer_prices, constraints),
-                    ),
-                )
+                // a device may reason at a coarser decision resolution than the
+                // native price points; aggregate, decide, then expand the chosen
+                // slots back to the underlying instants
+                let mut enabled = match constraints.resolution {
+                    Some(resolution) => {
+                        let (slots, mapping) =
+                            aggregate(&consumer_prices, resolution, constraints.reduction);
+                        satisfy_constraints(&slots, constraints)
+                            .iter()
+                            .flat_map(|slot| mapping.get(slot).cloned().unwrap_or_default())
+                            .collect()
+                    }
+                    None => satisfy_constraints(&consumer_prices, constraints),
+                };
+                // intersect with the device's availability window, if any
+                if let Some(rule) = schedules.get(name) {
+                    let permitted = permitted_instants(&consumer_prices, rule);
+                    enabled.retain(|t| permitted.contains(t));
+                }
+                (name, (constraints, enabled))
             })
             .collect();
 
@@ -265,6 +903,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         if opt.dry_run {
             break;
         }
+        day = next_day;
     }
     Ok(())
 }